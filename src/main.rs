@@ -1,5 +1,6 @@
 use async_std::{path::Path, task};
 use scraper::{element_ref::ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
 type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
@@ -29,6 +30,130 @@ fn unix_secs() -> u64 {
         .as_secs()
 }
 
+/// base delay for the exponential backoff between download retries
+const RETRY_BACKOFF_BASE_MS: u64 = 500;
+
+/// redirects to follow before giving up, so a soft-deleted post's redirect
+/// loop can't hang the scraper
+const MAX_REDIRECTS: usize = 10;
+
+/// builds the `reqwest::Client` used for every request, wiring in an
+/// optional proxy and a redirect policy that errors out on iFunny's
+/// `/404` redirect instead of following it
+fn build_client(opts: &ScrapeOpt) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::custom(
+        |attempt| {
+            if attempt.url().path() == "/404" {
+                attempt.error("redirected to /404")
+            } else if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        },
+    ));
+    if let Some(proxy) = &opts.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// session cookies persisted to disk between runs so authenticated or
+/// region-gated timelines keep working across invocations
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CookieJar {
+    cookies: std::collections::HashMap<String, String>,
+}
+
+impl CookieJar {
+    async fn load<P: AsRef<Path>>(path: P) -> Self {
+        match async_std::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => CookieJar::default(),
+        }
+    }
+
+    async fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        async_std::fs::write(path, json).await?;
+        Ok(())
+    }
+
+    fn header_value(&self) -> String {
+        let mut parts: Vec<String> = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        parts.push("mode=list".to_string());
+        format!("{};", parts.join("; "))
+    }
+
+    fn absorb(&mut self, headers: &reqwest::header::HeaderMap) {
+        for value in headers.get_all(reqwest::header::SET_COOKIE) {
+            if let Ok(raw) = value.to_str() {
+                if let Some((name, value)) = raw.split(';').next().and_then(|p| p.split_once('=')) {
+                    self.cookies
+                        .insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+    }
+}
+
+/// shared token-bucket limiter so page scraping and downloads draw from one
+/// politeness budget instead of two unrelated pacing mechanisms
+struct RateLimiter {
+    state: async_std::sync::Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    last_refill: std::time::Instant,
+}
+
+impl RateLimiter {
+    fn new(requests_per_minute: u32) -> Self {
+        let capacity = requests_per_minute.max(1) as f64;
+        RateLimiter {
+            state: async_std::sync::Mutex::new(RateLimiterState {
+                tokens: capacity,
+                capacity,
+                refill_per_sec: capacity / 60.0,
+                last_refill: std::time::Instant::now(),
+            }),
+        }
+    }
+
+    /// blocks until a token is available, refilling based on elapsed time
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = std::time::Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * state.refill_per_sec).min(state.capacity);
+                state.last_refill = now;
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    None
+                } else {
+                    let deficit = 1.0 - state.tokens;
+                    Some(std::time::Duration::from_secs_f64(
+                        deficit / state.refill_per_sec,
+                    ))
+                }
+            };
+            match wait {
+                None => return,
+                Some(d) => task::sleep(d).await,
+            }
+        }
+    }
+}
+
 use argh::FromArgs;
 
 #[derive(FromArgs, Debug)]
@@ -45,6 +170,83 @@ struct ScrapeOpt {
     /// how many milliseconds to wait before scraping the next page of memes
     #[argh(option, short = 'p', default = "999")]
     num_pages: usize,
+
+    /// how many times to attempt a single download (first try included) before giving up on it
+    #[argh(option, short = 'r', default = "5")]
+    retries: usize,
+
+    /// how many downloads to run at once
+    #[argh(option, short = 'j', default = "8")]
+    concurrency: usize,
+
+    /// max requests per minute shared across page scraping and downloads
+    #[argh(option, default = "8")]
+    rate: u32,
+
+    /// how many pixels of watermark to crop off the bottom of each image; 0 disables cropping
+    #[argh(option, default = "20")]
+    crop_px: u32,
+
+    /// write original image bytes untouched instead of cropping and re-encoding them
+    #[argh(switch)]
+    no_strip: bool,
+
+    /// write an RSS 2.0 feed of the collected links to this path
+    #[cfg(feature = "rss")]
+    #[argh(option)]
+    feed: Option<String>,
+
+    /// proxy URL to route all requests through, e.g. http://localhost:8080
+    #[argh(option)]
+    proxy: Option<String>,
+
+    /// persist session cookies to cookies.json between runs
+    #[argh(switch)]
+    keep_session: bool,
+}
+
+/// on-disk record of what's already been archived for a user, so a second
+/// run only has to fetch what's new
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    url: String,
+    file_name: String,
+    #[serde(default)]
+    is_video: bool,
+}
+
+impl ManifestEntry {
+    fn as_link(&self) -> IFunnyLink {
+        if self.is_video {
+            IFunnyLink::Moving(self.url.clone())
+        } else {
+            IFunnyLink::Still(self.url.clone())
+        }
+    }
+}
+
+impl Manifest {
+    async fn load<P: AsRef<Path>>(path: P) -> Self {
+        match async_std::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => Manifest::default(),
+        }
+    }
+
+    async fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        async_std::fs::write(path, json).await?;
+        Ok(())
+    }
+
+    fn seen_urls(&self) -> HashSet<String> {
+        self.entries.iter().map(|e| e.url.clone()).collect()
+    }
 }
 
 #[tokio::main]
@@ -53,54 +255,219 @@ async fn main() -> Result<()> {
 }
 
 async fn archive_user(opts: ScrapeOpt) -> Result<()> {
-    use futures::stream::TryStreamExt;
+    use futures::stream::StreamExt;
     use std::time::Instant;
     let dl_folder = Path::new(&*opts.username);
     if !dl_folder.exists().await {
         std::fs::create_dir_all(&dl_folder)?;
     }
-    let client = reqwest::Client::new();
+    let manifest_path = dl_folder.join("manifest.json");
+    let mut manifest = Manifest::load(&manifest_path).await;
+    let mut seen_cache = manifest.seen_urls();
+    let cookies_path = dl_folder.join("cookies.json");
+    let mut cookies = if opts.keep_session {
+        CookieJar::load(&cookies_path).await
+    } else {
+        CookieJar::default()
+    };
+    let client = build_client(&opts)?;
+    let limiter = std::sync::Arc::new(RateLimiter::new(opts.rate));
     let before = Instant::now();
+    // always start at the current timestamp so the first page re-reads the
+    // top of the timeline; the manifest's seen_cache + the all-seen-page
+    // stop condition below are what make a resumed run cheap, not the cursor
     let media_links = get_links(
         &client,
         &*opts.username,
         opts.num_pages,
         opts.page_scrape_delay_ms,
+        &mut seen_cache,
+        &limiter,
+        &mut cookies,
     )
     .await?;
+    if opts.keep_session {
+        cookies.save(&cookies_path).await?;
+    }
     let link_count = media_links.len();
     let elapsed = before.elapsed();
-    quick_save_links(media_links.as_slice(), dl_folder.join("links.txt")).await?;
-    println!("Collected {} links in {:?}", link_count, elapsed);
+    println!("Collected {} new links in {:?}", link_count, elapsed);
     let before_dl = Instant::now();
-    let futs = futures::stream::FuturesUnordered::new();
-    for link in &media_links {
-        futs.push(link.download(dl_folder.join(link.file_name())));
+    let retries = opts.retries;
+    let crop_px = opts.crop_px;
+    let strip = !opts.no_strip;
+    let concurrency = opts.concurrency.max(1);
+    // a link already on disk still belongs in the manifest (it's part of the
+    // archive, just not downloaded this run) - only a hard failure should be
+    // left out of links.txt/the feed
+    enum DownloadOutcome {
+        Skipped(ManifestEntry),
+        Downloaded(ManifestEntry),
+        Failed(String),
+    }
+    let outcomes: Vec<DownloadOutcome> = futures::stream::iter(&media_links)
+        .map(|link| {
+            let filename = dl_folder.join(link.file_name());
+            let limiter = limiter.clone();
+            let client = client.clone();
+            let is_video = matches!(link, IFunnyLink::Moving(_));
+            async move {
+                let entry = || ManifestEntry {
+                    url: link.url().to_string(),
+                    file_name: link.file_name(),
+                    is_video,
+                };
+                if filename.exists().await {
+                    return DownloadOutcome::Skipped(entry());
+                }
+                match download_with_retry(link, &filename, retries, &limiter, crop_px, strip, &client)
+                    .await
+                {
+                    Ok(()) => DownloadOutcome::Downloaded(entry()),
+                    Err(_) => DownloadOutcome::Failed(link.url().to_string()),
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let mut skipped = 0usize;
+    let mut attempted = 0usize;
+    let mut failed_urls = Vec::new();
+    for outcome in outcomes {
+        match outcome {
+            DownloadOutcome::Skipped(entry) => {
+                skipped += 1;
+                manifest.entries.push(entry);
+            }
+            DownloadOutcome::Downloaded(entry) => {
+                attempted += 1;
+                manifest.entries.push(entry);
+            }
+            DownloadOutcome::Failed(url) => {
+                attempted += 1;
+                failed_urls.push(url);
+            }
+        }
+    }
+    manifest.save(&manifest_path).await?;
+    // links.txt and the feed describe the whole archive, not just this
+    // run's delta, so rebuild them from the manifest's full entry list
+    let all_links: Vec<IFunnyLink> = manifest.entries.iter().map(ManifestEntry::as_link).collect();
+    quick_save_links(all_links.as_slice(), dl_folder.join("links.txt")).await?;
+    #[cfg(feature = "rss")]
+    if let Some(feed_path) = &opts.feed {
+        write_feed(&opts.username, &all_links, feed_path).await?;
     }
-    let _results: Vec<_> = futs.try_collect().await?;
+    let succeeded = attempted - failed_urls.len();
     println!(
-        "Downloaded {} items in {:?}",
-        link_count,
+        "Downloaded {} items ({} skipped, already on disk) in {:?}",
+        succeeded,
+        skipped,
         before_dl.elapsed()
     );
+    if !failed_urls.is_empty() {
+        println!("{} items failed permanently, see failed.txt", failed_urls.len());
+        async_std::fs::write(dl_folder.join("failed.txt"), failed_urls.join("\n")).await?;
+    }
     Ok(())
 }
 
-async fn download_ifunny_image<P: AsRef<Path>>(url: &str, location: P) -> Result<()> {
+/// tries a single download up to `max_attempts` times in all, sleeping with
+/// exponential backoff between tries, instead of letting one bad transfer
+/// take down the whole batch
+async fn download_with_retry<P: AsRef<Path>>(
+    link: &IFunnyLink,
+    location: P,
+    max_attempts: usize,
+    limiter: &RateLimiter,
+    crop_px: u32,
+    strip: bool,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let mut attempt: u32 = 0;
+    loop {
+        match link
+            .download(location.as_ref(), limiter, crop_px, strip, client)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                attempt += 1;
+                if attempt as usize >= max_attempts {
+                    return Err(err);
+                }
+                // cap the exponent so a large --retries count can't saturate
+                // the backoff to u64::MAX ms; it is already ~9 minutes at the cap
+                let backoff_ms =
+                    RETRY_BACKOFF_BASE_MS.saturating_mul(2u64.saturating_pow((attempt - 1).min(16)));
+                task::sleep(std::time::Duration::from_millis(backoff_ms)).await;
+            }
+        }
+    }
+}
+
+async fn download_ifunny_image<P: AsRef<Path>>(
+    url: &str,
+    location: P,
+    limiter: &RateLimiter,
+    crop_px: u32,
+    strip: bool,
+    client: &reqwest::Client,
+) -> Result<()> {
     use image::GenericImageView;
-    let image_reader = reqwest::get(url).await?.bytes().await?;
-    let image = image::io::Reader::new(std::io::Cursor::new(image_reader))
-        .with_guessed_format()?
-        .decode()?;
-    let crop = image.crop_imm(0, 0, image.width(), image.height() - 20);
+    limiter.acquire().await;
+    let bytes = client.get(url).send().await?.bytes().await?;
+    // nothing to crop: write the original bytes untouched rather than
+    // paying for a lossy decode/re-encode round-trip
+    if !strip || crop_px == 0 {
+        async_std::fs::write(location, bytes.as_ref()).await?;
+        return Ok(());
+    }
+    let reader = image::io::Reader::new(std::io::Cursor::new(bytes.clone())).with_guessed_format()?;
+    let format = reader.format().unwrap_or(image::ImageFormat::Jpeg);
+    // some formats/variants the image crate can't even decode (e.g. WebP
+    // with an alpha channel); ship the uncropped original rather than
+    // failing the download outright. a genuinely corrupt/truncated
+    // download is a different problem, so only swallow unsupported-format
+    // errors here and let anything else propagate to the retry loop
+    let image = match reader.decode() {
+        Ok(image) => image,
+        Err(image::ImageError::Unsupported(_)) => {
+            async_std::fs::write(location, bytes.as_ref()).await?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let height = image.height();
+    if crop_px >= height {
+        async_std::fs::write(location, bytes.as_ref()).await?;
+        return Ok(());
+    }
+    let crop = image.crop_imm(0, 0, image.width(), height - crop_px);
     let mut fout = Vec::<u8>::new();
-    crop.write_to(&mut fout, image::ImageFormat::Jpeg)?;
+    // some formats the image crate can decode but not re-encode (e.g. WebP);
+    // fall back to Jpeg, and as a last resort ship the uncropped original
+    // rather than failing the download outright
+    if crop.write_to(&mut fout, format).is_err() {
+        fout.clear();
+        if crop.write_to(&mut fout, image::ImageFormat::Jpeg).is_err() {
+            async_std::fs::write(location, bytes.as_ref()).await?;
+            return Ok(());
+        }
+    }
     async_std::fs::write(location, fout.as_slice()).await?;
     Ok(())
 }
 
-async fn download_ifunny_video<P: AsRef<Path>>(url: &str, location: P) -> Result<()> {
-    let video_reader = reqwest::get(url).await?.bytes().await?;
+async fn download_ifunny_video<P: AsRef<Path>>(
+    url: &str,
+    location: P,
+    limiter: &RateLimiter,
+    client: &reqwest::Client,
+) -> Result<()> {
+    limiter.acquire().await;
+    let video_reader = client.get(url).send().await?.bytes().await?;
     async_std::fs::write(location, video_reader).await?;
     Ok(())
 }
@@ -122,12 +489,86 @@ async fn quick_save_links<P: AsRef<Path>>(links: &[IFunnyLink], location: P) ->
     Ok(())
 }
 
+/// serializes the collected links as an RSS 2.0 feed so a user's timeline
+/// can be followed from any podcast/feed reader
+#[cfg(feature = "rss")]
+async fn write_feed<P: AsRef<Path>>(username: &str, links: &[IFunnyLink], location: P) -> Result<()> {
+    use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+    use quick_xml::Writer;
+    use std::io::Cursor;
+
+    let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+    let mut rss_start = BytesStart::new("rss");
+    rss_start.push_attribute(("version", "2.0"));
+    writer.write_event(Event::Start(rss_start))?;
+    writer.write_event(Event::Start(BytesStart::new("channel")))?;
+
+    let channel_link = format!("https://ifunny.co/user/{}", username);
+
+    writer.write_event(Event::Start(BytesStart::new("title")))?;
+    writer.write_event(Event::Text(BytesText::new(&format!(
+        "{}'s iFunny timeline",
+        username
+    ))))?;
+    writer.write_event(Event::End(BytesEnd::new("title")))?;
+
+    writer.write_event(Event::Start(BytesStart::new("link")))?;
+    writer.write_event(Event::Text(BytesText::new(&channel_link)))?;
+    writer.write_event(Event::End(BytesEnd::new("link")))?;
+
+    writer.write_event(Event::Start(BytesStart::new("description")))?;
+    writer.write_event(Event::Text(BytesText::new(&format!(
+        "Archived posts from {}'s iFunny timeline",
+        username
+    ))))?;
+    writer.write_event(Event::End(BytesEnd::new("description")))?;
+
+    for link in links {
+        writer.write_event(Event::Start(BytesStart::new("item")))?;
+
+        writer.write_event(Event::Start(BytesStart::new("title")))?;
+        writer.write_event(Event::Text(BytesText::new(&link.file_name())))?;
+        writer.write_event(Event::End(BytesEnd::new("title")))?;
+
+        writer.write_event(Event::Start(BytesStart::new("guid")))?;
+        writer.write_event(Event::Text(BytesText::new(link.url())))?;
+        writer.write_event(Event::End(BytesEnd::new("guid")))?;
+
+        let mime = match link {
+            IFunnyLink::Moving(_) => "video/mp4",
+            IFunnyLink::Still(_) => "image/jpeg",
+        };
+        let mut enclosure = BytesStart::new("enclosure");
+        enclosure.push_attribute(("url", link.url()));
+        enclosure.push_attribute(("type", mime));
+        writer.write_event(Event::Empty(enclosure))?;
+
+        writer.write_event(Event::End(BytesEnd::new("item")))?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("channel")))?;
+    writer.write_event(Event::End(BytesEnd::new("rss")))?;
+
+    let bytes = writer.into_inner().into_inner();
+    async_std::fs::write(location, bytes).await?;
+    Ok(())
+}
+
 impl IFunnyLink {
-    async fn download<P: AsRef<Path>>(&self, location: P) -> Result<()> {
+    async fn download<P: AsRef<Path>>(
+        &self,
+        location: P,
+        limiter: &RateLimiter,
+        crop_px: u32,
+        strip: bool,
+        client: &reqwest::Client,
+    ) -> Result<()> {
         use IFunnyLink::*;
         match self {
-            Moving(url) => download_ifunny_video(&url, location).await,
-            Still(url) => download_ifunny_image(&url, location).await,
+            Moving(url) => download_ifunny_video(url, location, limiter, client).await,
+            Still(url) => download_ifunny_image(url, location, limiter, crop_px, strip, client).await,
         }
     }
 
@@ -153,10 +594,14 @@ async fn get_links(
     username: &str,
     num_pages: usize,
     delay_ms: u64,
+    seen_cache: &mut HashSet<String>,
+    limiter: &RateLimiter,
+    cookies: &mut CookieJar,
 ) -> Result<Vec<IFunnyLink>> {
     use std::io::Write;
     let mut links = Vec::new();
     let mut next_timestamp: Option<f64> = None;
+    let resuming = !seen_cache.is_empty();
 
     let posts_selector = Selector::parse("body").expect("Failed to construct posts CSS selector");
     let vid_media_selector = Selector::parse("div > div.post__media > div")
@@ -164,7 +609,6 @@ async fn get_links(
     let img_media_selector = Selector::parse("div > div.post__media > div > div > a > img")
         .expect("Failed to construct image media CSS selector");
 
-    let mut seen_cache: HashSet<String> = HashSet::new();
     let mut duplicate_hits: usize = 0;
 
     for page in 0..num_pages {
@@ -182,20 +626,23 @@ async fn get_links(
             "https://ifunny.co/user/{}/timeline/{}?page={}&mode=list",
             username, time_unix, page
         );
-        let html = client
+        limiter.acquire().await;
+        let response = client
             .get(&url)
             .header("X-Requested-With", "XMLHttpRequest")
-            .header("Cookie", "mode=list;")
+            .header("Cookie", cookies.header_value())
             .send()
-            .await?
-            .text()
             .await?;
+        cookies.absorb(response.headers());
+        let html = response.text().await?;
         if html.is_empty() {
             println!();
             return Err(Box::new(ScrapeError::RateLimited));
         }
         // std::fs::write("latest.html", &html)?;
         let document = Html::parse_document(&html);
+        let mut page_media_count: usize = 0;
+        let mut page_new_count: usize = 0;
         if let Some(body) = document.select(&posts_selector).next() {
             for node in body.children() {
                 if let Some(elem) = ElementRef::wrap(node) {
@@ -210,6 +657,7 @@ async fn get_links(
                     match elem.select(&vid_media_selector).next() {
                         Some(e) => {
                             if let Some(source) = e.value().attr("data-source") {
+                                page_media_count += 1;
                                 let owned = source.to_string();
                                 if seen_cache.contains(&owned) {
                                     duplicate_hits += 1;
@@ -218,6 +666,7 @@ async fn get_links(
                                         return Ok(links);
                                     }
                                 } else {
+                                    page_new_count += 1;
                                     seen_cache.insert(owned.clone());
                                     links.push(IFunnyLink::Moving(owned));
                                 }
@@ -225,6 +674,7 @@ async fn get_links(
                                 match elem.select(&img_media_selector).next() {
                                     Some(e) => {
                                         if let Some(source) = e.value().attr("data-src") {
+                                            page_media_count += 1;
                                             let owned = source.to_string();
                                             if seen_cache.contains(&owned) {
                                                 duplicate_hits += 1;
@@ -233,6 +683,7 @@ async fn get_links(
                                                     return Ok(links);
                                                 }
                                             } else {
+                                                page_new_count += 1;
                                                 seen_cache.insert(owned.clone());
                                                 links.push(IFunnyLink::Still(owned));
                                             }
@@ -247,6 +698,12 @@ async fn get_links(
                 }
             }
         }
+        // when resuming from a manifest, a page with no links we haven't
+        // already archived means we've caught back up to the last run
+        if resuming && page_media_count > 0 && page_new_count == 0 {
+            println!();
+            return Ok(links);
+        }
     }
 
     println!();